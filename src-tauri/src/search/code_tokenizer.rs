@@ -0,0 +1,221 @@
+use std::ops::Range;
+
+use tantivy::tokenizer::{BoxTokenStream, Token, TokenStream, Tokenizer};
+
+pub const CODE_SPLIT_TOKENIZER: &str = "code_splitter";
+pub const CODE_NGRAM_TOKENIZER: &str = "code_ngram";
+
+/// Caps the tokens a single field value can contribute, so a minified or
+/// generated file with no natural word boundaries can't blow up the index.
+const MAX_TOKENS_PER_FIELD: usize = 20_000;
+
+/// Splits identifiers on camelCase/PascalCase boundaries and on
+/// `_`/`-`/`.`/`/` separators, lowercasing every word it yields.
+#[derive(Clone, Default)]
+pub struct CodeSplitTokenizer;
+
+impl Tokenizer for CodeSplitTokenizer {
+    fn token_stream<'a>(&self, text: &'a str) -> BoxTokenStream<'a> {
+        BoxTokenStream::from(VecTokenStream::new(
+            split_words(text)
+                .into_iter()
+                .enumerate()
+                .map(|(position, (range, word))| Token {
+                    offset_from: range.start,
+                    offset_to: range.end,
+                    position,
+                    text: word,
+                    position_length: 1,
+                })
+                .collect(),
+        ))
+    }
+}
+
+/// Layers edge- and inner-n-grams on top of [`CodeSplitTokenizer`]'s word
+/// boundaries, so a substring of an identifier (e.g. `Config` inside
+/// `parseConfig`) becomes searchable on its own.
+#[derive(Clone)]
+pub struct CodeNgramTokenizer {
+    min_gram: usize,
+    max_gram: usize,
+}
+
+impl CodeNgramTokenizer {
+    pub fn new(min_gram: usize, max_gram: usize) -> Self {
+        Self { min_gram, max_gram }
+    }
+}
+
+impl Tokenizer for CodeNgramTokenizer {
+    fn token_stream<'a>(&self, text: &'a str) -> BoxTokenStream<'a> {
+        let mut tokens = Vec::new();
+        'words: for (position, (range, word)) in split_words(text).into_iter().enumerate() {
+            let chars: Vec<char> = word.chars().collect();
+            let max_gram = self.max_gram.min(chars.len());
+            for start in 0..chars.len() {
+                for len in self.min_gram..=max_gram {
+                    if start + len > chars.len() {
+                        break;
+                    }
+                    if tokens.len() >= MAX_TOKENS_PER_FIELD {
+                        break 'words;
+                    }
+                    tokens.push(Token {
+                        offset_from: range.start,
+                        offset_to: range.end,
+                        position,
+                        text: chars[start..start + len].iter().collect(),
+                        position_length: 1,
+                    });
+                }
+            }
+        }
+        BoxTokenStream::from(VecTokenStream::new(tokens))
+    }
+}
+
+struct VecTokenStream {
+    tokens: Vec<Token>,
+    index: usize,
+    token: Token,
+}
+
+impl VecTokenStream {
+    fn new(tokens: Vec<Token>) -> Self {
+        Self {
+            tokens,
+            index: 0,
+            token: Token::default(),
+        }
+    }
+}
+
+impl TokenStream for VecTokenStream {
+    fn advance(&mut self) -> bool {
+        if self.index >= self.tokens.len() {
+            return false;
+        }
+        self.token = self.tokens[self.index].clone();
+        self.index += 1;
+        true
+    }
+
+    fn token(&self) -> &Token {
+        &self.token
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        &mut self.token
+    }
+}
+
+/// Splits `text` into word-boundary substrings: a new word starts on a
+/// lower->upper transition, a `_`/`-`/`.`/`/` separator (which is dropped),
+/// or an alpha<->digit transition. Everything else is lowercased in place.
+fn split_words(text: &str) -> Vec<(Range<usize>, String)> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut current_start = 0usize;
+    let mut prev: Option<char> = None;
+
+    let is_separator = |c: char| matches!(c, '_' | '-' | '.' | '/');
+
+    for (offset, c) in text.char_indices() {
+        if is_separator(c) {
+            if !current.is_empty() {
+                words.push((current_start..offset, std::mem::take(&mut current)));
+            }
+            prev = None;
+            continue;
+        }
+
+        let is_boundary = match prev {
+            Some(p) => {
+                (p.is_lowercase() && c.is_uppercase())
+                    || (p.is_alphabetic() && c.is_numeric())
+                    || (p.is_numeric() && c.is_alphabetic())
+            }
+            None => false,
+        };
+
+        if (is_boundary || current.is_empty()) && current.is_empty() {
+            current_start = offset;
+        } else if is_boundary {
+            words.push((current_start..offset, std::mem::take(&mut current)));
+            current_start = offset;
+        }
+
+        current.extend(c.to_lowercase());
+        prev = Some(c);
+    }
+    if !current.is_empty() {
+        words.push((current_start..text.len(), current));
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(text: &str) -> Vec<String> {
+        split_words(text).into_iter().map(|(_, word)| word).collect()
+    }
+
+    fn tokens<T: Tokenizer>(tokenizer: &T, text: &str) -> Vec<String> {
+        let mut stream = tokenizer.token_stream(text);
+        let mut tokens = Vec::new();
+        stream.process(&mut |token| tokens.push(token.text.clone()));
+        tokens
+    }
+
+    #[test]
+    fn splits_camel_case() {
+        assert_eq!(words("parseConfig"), vec!["parse", "config"]);
+    }
+
+    #[test]
+    fn splits_pascal_case() {
+        assert_eq!(words("ParseConfig"), vec!["parse", "config"]);
+    }
+
+    #[test]
+    fn splits_on_separators() {
+        assert_eq!(
+            words("parse_config-value.ext/path"),
+            vec!["parse", "config", "value", "ext", "path"]
+        );
+    }
+
+    #[test]
+    fn splits_on_alpha_digit_transitions() {
+        assert_eq!(words("v2Config"), vec!["v", "2", "config"]);
+    }
+
+    #[test]
+    fn code_split_tokenizer_matches_split_words() {
+        assert_eq!(
+            tokens(&CodeSplitTokenizer, "parse_httpClient.go"),
+            vec!["parse", "http", "client", "go"]
+        );
+    }
+
+    #[test]
+    fn ngram_tokenizer_emits_edge_and_inner_grams_within_bounds() {
+        let grams = tokens(&CodeNgramTokenizer::new(2, 10), "config");
+        // every gram is between min_gram and max_gram chars long...
+        assert!(grams.iter().all(|g| (2..=10).contains(&g.chars().count())));
+        // ...and includes both an edge gram and an inner gram of the word.
+        assert!(grams.contains(&"co".to_string()));
+        assert!(grams.contains(&"nfi".to_string()));
+        assert!(grams.contains(&"config".to_string()));
+    }
+
+    #[test]
+    fn ngram_tokenizer_caps_tokens_on_pathological_input() {
+        let minified = "a".repeat(5_000);
+        let grams = tokens(&CodeNgramTokenizer::new(2, 10), &minified);
+        assert_eq!(grams.len(), MAX_TOKENS_PER_FIELD);
+    }
+}
@@ -1,5 +1,8 @@
+mod code_tokenizer;
+
 use crate::{deltas, projects, sessions, storage};
 use anyhow::{Context, Result};
+use code_tokenizer::{CodeNgramTokenizer, CodeSplitTokenizer, CODE_NGRAM_TOKENIZER, CODE_SPLIT_TOKENIZER};
 use serde::Serialize;
 use similar::{ChangeTag, TextDiff};
 use std::ops::Range;
@@ -9,9 +12,16 @@ use std::{
     sync::{Arc, Mutex},
     time, vec,
 };
-use tantivy::{collector, directory::MmapDirectory, schema, IndexWriter};
+use tantivy::{
+    collector,
+    directory::MmapDirectory,
+    query::{BooleanQuery, ConstScoreQuery, FuzzyTermQuery, Occur, PhraseQuery, Query, TermQuery},
+    schema,
+    tokenizer::{TokenStream, Tokenizer},
+    IndexWriter, Term,
+};
 
-const CURRENT_VERSION: u64 = 3; // should not decrease
+const CURRENT_VERSION: u64 = 9; // should not decrease
 
 #[derive(Clone)]
 struct MetaStorage {
@@ -75,6 +85,13 @@ impl Deltas {
             .settings(index_settings)
             .open_or_create(mmap_dir)?;
 
+        index
+            .tokenizers()
+            .register(CODE_SPLIT_TOKENIZER, CodeSplitTokenizer);
+        index
+            .tokenizers()
+            .register(CODE_NGRAM_TOKENIZER, CodeNgramTokenizer::new(2, 10));
+
         let reader = index.reader()?;
         let writer = index.writer_with_num_threads(1, WRITE_BUFFER_SIZE)?;
 
@@ -86,10 +103,23 @@ impl Deltas {
         })
     }
 
-    pub fn search(&self, query: &SearchQuery) -> Result<Vec<SearchResult>> {
+    pub fn search(&self, query: &SearchQuery) -> Result<SearchResults> {
         search(&self.index, &self.reader, query)
     }
 
+    /// Merges the index down to a handful of segments and reclaims the
+    /// space held by deleted documents. Safe to call periodically, or after
+    /// a full [`Deltas::reindex_project`].
+    pub fn optimize(&mut self) -> Result<()> {
+        let segment_ids = self.index.searchable_segment_ids()?;
+        let mut writer = self.writer.lock().unwrap();
+        if segment_ids.len() > 1 {
+            writer.merge(&segment_ids).wait()?;
+        }
+        writer.garbage_collect_files().wait()?;
+        Ok(())
+    }
+
     pub fn reindex_project(
         &mut self,
         repo: &git2::Repository,
@@ -124,7 +154,7 @@ impl Deltas {
             let session = sessions::Session::from_commit(repo, &commit).with_context(|| {
                 format!("Could not parse commit {} in project", oid.to_string())
             })?;
-            if let Err(e) = self.index_session(repo, project, &session) {
+            if let Err(e) = self.index_session(repo, project, &commit, &session) {
                 log::error!(
                     "Could not index commit {} in {}: {:#}",
                     oid,
@@ -133,6 +163,8 @@ impl Deltas {
                 );
             }
         }
+        self.optimize()?;
+
         log::info!(
             "Reindexing project {} done, took {}ms",
             project.path,
@@ -145,12 +177,14 @@ impl Deltas {
         &mut self,
         repo: &git2::Repository,
         project: &projects::Project,
+        commit: &git2::Commit,
         session: &sessions::Session,
     ) -> Result<()> {
         log::info!("Indexing session {} in {}", session.id, project.path);
         index_session(
             &self.index,
             &mut self.writer.lock().unwrap(),
+            commit,
             session,
             repo,
             project,
@@ -161,16 +195,40 @@ impl Deltas {
     }
 }
 
+/// Text options for a field indexed with one of our code-aware tokenizers:
+/// stored (so snippets/highlighting still render the real, untokenized
+/// text) and indexed with positions (needed for phrase queries/snippets).
+fn code_text_options(tokenizer: &str) -> schema::TextOptions {
+    schema::TextOptions::default().set_stored().set_indexing_options(
+        schema::TextFieldIndexing::default()
+            .set_tokenizer(tokenizer)
+            .set_index_option(schema::IndexRecordOption::WithFreqsAndPositions),
+    )
+}
+
 fn build_schema() -> schema::Schema {
     let mut schema_builder = schema::Schema::builder();
     schema_builder.add_u64_field("version", schema::INDEXED | schema::FAST);
     schema_builder.add_text_field("project_id", schema::TEXT | schema::STORED | schema::FAST);
-    schema_builder.add_text_field("session_id", schema::STORED);
+    schema_builder.add_text_field("session_id", schema::STRING | schema::STORED);
     schema_builder.add_u64_field("index", schema::STORED);
-    schema_builder.add_text_field("file_path", schema::TEXT | schema::STORED | schema::FAST);
-    schema_builder.add_text_field("diff", schema::TEXT | schema::STORED);
-    schema_builder.add_bool_field("is_addition", schema::FAST);
-    schema_builder.add_bool_field("is_deletion", schema::FAST);
+    schema_builder.add_text_field("file_path", code_text_options(CODE_SPLIT_TOKENIZER) | schema::FAST);
+    schema_builder.add_text_field("diff", code_text_options(CODE_NGRAM_TOKENIZER));
+    // whole-word index of `diff`, used only by fuzzy search: `diff` itself
+    // is truncated to `code_ngram`'s max gram length, so a fuzzy match
+    // against it can't bridge identifiers longer than max_gram + distance
+    schema_builder.add_text_field("diff_words", code_text_options(CODE_SPLIT_TOKENIZER));
+    schema_builder.add_bool_field("is_addition", schema::INDEXED | schema::FAST);
+    schema_builder.add_bool_field("is_deletion", schema::INDEXED | schema::FAST);
+    schema_builder.add_facet_field("file_extension", schema::FacetOptions::default().set_stored());
+    schema_builder.add_text_field("author_name", schema::TEXT | schema::STORED);
+    schema_builder.add_text_field(
+        "author_email",
+        schema::STRING | schema::STORED | schema::FAST,
+    );
+    schema_builder.add_text_field("branch", schema::STRING | schema::STORED | schema::FAST);
+    schema_builder.add_u64_field("session_start_ms", schema::INDEXED | schema::FAST);
+    schema_builder.add_u64_field("session_end_ms", schema::INDEXED | schema::FAST);
     schema_builder.add_u64_field("timestamp_ms", schema::INDEXED | schema::FAST);
     schema_builder.build()
 }
@@ -185,11 +243,15 @@ pub struct SearchResult {
     pub file_path: String,
     pub index: u64,
     pub highlighted: Vec<String>,
+    pub author_name: String,
+    pub author_email: String,
+    pub branch: String,
 }
 
 fn index_session(
     index: &tantivy::Index,
     writer: &mut IndexWriter,
+    commit: &git2::Commit,
     session: &sessions::Session,
     repo: &git2::Repository,
     project: &projects::Project,
@@ -206,6 +268,14 @@ fn index_session(
         &session.id,
         Some(deltas.keys().map(|k| k.as_str()).collect()),
     )?;
+
+    // remove any documents left over from a previous (possibly
+    // out-of-version) indexing of this session, so reindexing is idempotent
+    writer.delete_term(Term::from_field_text(
+        index.schema().get_field("session_id").unwrap(),
+        &session.id,
+    ));
+
     // index every file
     for (file_path, deltas) in deltas.into_iter() {
         // keep the state of the file after each delta operation
@@ -221,6 +291,7 @@ fn index_session(
             index_delta(
                 index,
                 writer,
+                commit,
                 session,
                 project,
                 &mut file_text,
@@ -237,6 +308,7 @@ fn index_session(
 fn index_delta(
     index: &tantivy::Index,
     writer: &mut IndexWriter,
+    commit: &git2::Commit,
     session: &sessions::Session,
     project: &projects::Project,
     file_text: &mut Vec<char>,
@@ -263,6 +335,32 @@ fn index_delta(
         index.schema().get_field("timestamp_ms").unwrap(),
         delta.timestamp_ms.try_into()?,
     );
+    doc.add_facet(
+        index.schema().get_field("file_extension").unwrap(),
+        extension_facet(&file_extension(file_path)),
+    );
+
+    let author = commit.author();
+    doc.add_text(
+        index.schema().get_field("author_name").unwrap(),
+        author.name().unwrap_or_default(),
+    );
+    doc.add_text(
+        index.schema().get_field("author_email").unwrap(),
+        author.email().unwrap_or_default(),
+    );
+    doc.add_text(
+        index.schema().get_field("branch").unwrap(),
+        session.meta.branch.clone().unwrap_or_default(),
+    );
+    doc.add_u64(
+        index.schema().get_field("session_start_ms").unwrap(),
+        session.meta.start_timestamp_ms.try_into()?,
+    );
+    doc.add_u64(
+        index.schema().get_field("session_end_ms").unwrap(),
+        session.meta.last_timestamp_ms.try_into()?,
+    );
 
     let prev_file_text = file_text.clone();
     // for every operation in the delta
@@ -277,15 +375,29 @@ fn index_delta(
     let new = &file_text.iter().collect::<String>();
 
     let all_changes = TextDiff::from_words(old, new);
+    let mut has_insert = false;
+    let mut has_delete = false;
     let changes = all_changes
         .iter_all_changes()
         .filter_map(|change| match change.tag() {
-            ChangeTag::Delete => change.as_str(),
-            ChangeTag::Insert => change.as_str(),
+            ChangeTag::Delete => {
+                has_delete = true;
+                change.as_str()
+            }
+            ChangeTag::Insert => {
+                has_insert = true;
+                change.as_str()
+            }
             ChangeTag::Equal => None,
         })
         .collect::<String>();
 
+    let is_addition = (old.is_empty() && !new.is_empty()) || (has_insert && !has_delete);
+    let is_deletion = (new.is_empty() && !old.is_empty()) || (has_delete && !has_insert);
+    doc.add_bool(index.schema().get_field("is_addition").unwrap(), is_addition);
+    doc.add_bool(index.schema().get_field("is_deletion").unwrap(), is_deletion);
+
+    doc.add_text(index.schema().get_field("diff_words").unwrap(), changes.clone());
     doc.add_text(index.schema().get_field("diff").unwrap(), changes);
 
     writer.add_document(doc)?;
@@ -293,6 +405,58 @@ fn index_delta(
     Ok(())
 }
 
+/// Lowercased extension of `file_path`, or the empty string if it has none.
+fn file_extension(file_path: &str) -> String {
+    Path::new(file_path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("")
+        .to_lowercase()
+}
+
+/// Facet value for an (already-lowercased) file extension. Extensionless
+/// files (`Makefile`, `Dockerfile`, dotfiles) map to a `none` segment
+/// instead of the empty string, which would otherwise collapse onto the
+/// root facet `"/"` and be excluded from `FacetCollector::add_facet("/")`'s
+/// child counts and from the `extensions` filter.
+fn extension_facet(extension: &str) -> schema::Facet {
+    let segment = if extension.is_empty() {
+        "none"
+    } else {
+        extension
+    };
+    schema::Facet::from(format!("/{}", segment))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeType {
+    Addition,
+    Deletion,
+}
+
+/// How to order search results. Defaults to [`OrderBy::Timestamp`], keeping
+/// existing callers' behavior unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OrderBy {
+    #[default]
+    Timestamp,
+    Relevance,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtensionCount {
+    pub extension: String,
+    pub count: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchResults {
+    pub results: Vec<SearchResult>,
+    pub extension_counts: Vec<ExtensionCount>,
+}
+
 #[derive(Debug)]
 pub struct SearchQuery {
     pub q: String,
@@ -300,47 +464,227 @@ pub struct SearchQuery {
     pub limit: usize,
     pub offset: Option<usize>,
     pub range: Range<u64>,
+    pub change_type: Option<ChangeType>,
+    pub extensions: Option<Vec<String>>,
+    /// Opt-in typo tolerance: the max Levenshtein distance a term in `q` may
+    /// be matched at (actual distance is also capped by term length, see
+    /// [`fuzzy_distance_for_term`]). `None` keeps the default exact parser.
+    pub fuzzy: Option<u8>,
+    pub author: Option<String>,
+    pub branch: Option<String>,
+    pub order_by: OrderBy,
+}
+
+/// Caps the edit distance allowed for a query term so short tokens don't
+/// over-match: 0 for `<=3` chars, 1 for `<=6`, 2 beyond.
+fn fuzzy_distance_for_term(term: &str) -> u8 {
+    match term.chars().count() {
+        0..=3 => 0,
+        4..=6 => 1,
+        _ => 2,
+    }
+}
+
+/// Whether a one-character edit that moves two adjacent characters counts
+/// as a single edit (matches how users actually mistype) rather than two.
+const FUZZY_TRANSPOSITION_COST_ONE: bool = true;
+
+/// Tokenizes `q` on whole-word boundaries (not the `diff` field's own
+/// `code_ngram` analyzer, which would shred each identifier into 2-10 char
+/// grams and size the allowed distance off those grams instead of the word)
+/// and ANDs together a prefix-anchored [`FuzzyTermQuery`] per word, each
+/// capped at `max_distance`. The terms are matched against `diff_words`, a
+/// whole-word index of the same text kept alongside `diff` specifically for
+/// this: `diff`'s term dictionary is truncated to `code_ngram`'s max gram
+/// length, so a fuzzy match there can never bridge identifiers longer than
+/// max_gram + distance (most real-world identifiers).
+fn fuzzy_query(index: &tantivy::Index, q: &str, max_distance: u8) -> Result<Box<dyn Query>> {
+    let field = index.schema().get_field("diff_words").unwrap();
+    let mut token_stream = CodeSplitTokenizer.token_stream(q);
+
+    let mut clauses: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+    token_stream.process(&mut |token| {
+        let distance = fuzzy_distance_for_term(&token.text).min(max_distance);
+        let term = Term::from_field_text(field, &token.text);
+        clauses.push((
+            Occur::Must,
+            Box::new(FuzzyTermQuery::new_prefix(
+                term,
+                distance,
+                FUZZY_TRANSPOSITION_COST_ONE,
+            )) as Box<dyn Query>,
+        ));
+    });
+
+    Ok(Box::new(BooleanQuery::new(clauses)))
+}
+
+/// Wraps a filter-only clause so it contributes zero BM25 score, keeping it
+/// from skewing `OrderBy::Relevance` ranking (it still restricts *which*
+/// documents match, just not how they're scored).
+fn filter_clause(query: Box<dyn Query>) -> Box<dyn Query> {
+    Box::new(ConstScoreQuery::new(query, 0.0))
 }
 
 pub fn search(
     index: &tantivy::Index,
     reader: &tantivy::IndexReader,
     q: &SearchQuery,
-) -> Result<Vec<SearchResult>> {
-    let query = tantivy::query::QueryParser::for_index(
+) -> Result<SearchResults> {
+    let metadata_query = tantivy::query::QueryParser::for_index(
         index,
         vec![
-            index.schema().get_field("diff").unwrap(),
-            index.schema().get_field("file_path").unwrap(),
+            index.schema().get_field("version").unwrap(),
+            index.schema().get_field("project_id").unwrap(),
+            index.schema().get_field("timestamp_ms").unwrap(),
         ],
     )
     .parse_query(
         format!(
-            "version:\"{}\" AND project_id:\"{}\" AND timestamp_ms:[{} TO {}}} AND ({})",
-            CURRENT_VERSION, q.project_id, q.range.start, q.range.end, q.q,
+            "version:\"{}\" AND project_id:\"{}\" AND timestamp_ms:[{} TO {}}}",
+            CURRENT_VERSION, q.project_id, q.range.start, q.range.end,
         )
         .as_str(),
     )?;
 
+    let text_query = match q.fuzzy {
+        Some(max_distance) => fuzzy_query(index, &q.q, max_distance)?,
+        None => tantivy::query::QueryParser::for_index(
+            index,
+            vec![
+                index.schema().get_field("diff").unwrap(),
+                index.schema().get_field("file_path").unwrap(),
+            ],
+        )
+        .parse_query(&q.q)?,
+    };
+
+    let mut clauses: Vec<(Occur, Box<dyn Query>)> = vec![
+        (Occur::Must, filter_clause(metadata_query)),
+        (Occur::Must, text_query),
+    ];
+
+    if let Some(change_type) = q.change_type {
+        let field = index
+            .schema()
+            .get_field(match change_type {
+                ChangeType::Addition => "is_addition",
+                ChangeType::Deletion => "is_deletion",
+            })
+            .unwrap();
+        clauses.push((
+            Occur::Must,
+            filter_clause(Box::new(TermQuery::new(
+                Term::from_field_bool(field, true),
+                schema::IndexRecordOption::Basic,
+            ))),
+        ));
+    }
+
+    if let Some(extensions) = &q.extensions {
+        let field = index.schema().get_field("file_extension").unwrap();
+        let by_extension: Vec<(Occur, Box<dyn Query>)> = extensions
+            .iter()
+            .map(|extension| {
+                let term = Term::from_facet(field, &extension_facet(&extension.to_lowercase()));
+                (
+                    Occur::Should,
+                    Box::new(TermQuery::new(term, schema::IndexRecordOption::Basic)) as Box<dyn Query>,
+                )
+            })
+            .collect();
+        clauses.push((
+            Occur::Must,
+            filter_clause(Box::new(BooleanQuery::new(by_extension))),
+        ));
+    }
+
+    if let Some(author) = &q.author {
+        // `author` may be an exact email or a display name, so match either:
+        // the email field exactly, or an in-order phrase against author_name.
+        let email_field = index.schema().get_field("author_email").unwrap();
+        let name_field = index.schema().get_field("author_name").unwrap();
+        let mut by_author: Vec<(Occur, Box<dyn Query>)> = vec![(
+            Occur::Should,
+            Box::new(TermQuery::new(
+                Term::from_field_text(email_field, author),
+                schema::IndexRecordOption::Basic,
+            )) as Box<dyn Query>,
+        )];
+        let name_terms: Vec<Term> = author
+            .split_whitespace()
+            .map(|word| Term::from_field_text(name_field, &word.to_lowercase()))
+            .collect();
+        // a phrase query needs at least two terms, so a single word is
+        // matched with a plain term query instead
+        if name_terms.len() == 1 {
+            by_author.push((
+                Occur::Should,
+                Box::new(TermQuery::new(
+                    name_terms[0].clone(),
+                    schema::IndexRecordOption::Basic,
+                )) as Box<dyn Query>,
+            ));
+        } else if name_terms.len() > 1 {
+            by_author.push((
+                Occur::Should,
+                Box::new(PhraseQuery::new(name_terms)) as Box<dyn Query>,
+            ));
+        }
+        clauses.push((
+            Occur::Must,
+            filter_clause(Box::new(BooleanQuery::new(by_author))),
+        ));
+    }
+
+    if let Some(branch) = &q.branch {
+        let field = index.schema().get_field("branch").unwrap();
+        clauses.push((
+            Occur::Must,
+            filter_clause(Box::new(TermQuery::new(
+                Term::from_field_text(field, branch),
+                schema::IndexRecordOption::Basic,
+            ))),
+        ));
+    }
+
+    let query = BooleanQuery::new(clauses);
+
     reader.reload()?;
     let searcher = reader.searcher();
 
-    let top_docs = searcher.search(
-        &query,
-        &collector::TopDocs::with_limit(q.limit)
-            .and_offset(q.offset.unwrap_or(0))
-            .order_by_u64_field(index.schema().get_field("timestamp_ms").unwrap()),
-    )?;
+    let mut facet_collector = collector::FacetCollector::for_field("file_extension");
+    facet_collector.add_facet("/");
+
+    let top_docs_collector = collector::TopDocs::with_limit(q.limit).and_offset(q.offset.unwrap_or(0));
+    let (doc_addresses, facet_counts): (Vec<tantivy::DocAddress>, collector::FacetCounts) = match q.order_by
+    {
+        OrderBy::Timestamp => {
+            let (top_docs, counts) = searcher.search(
+                &query,
+                &(
+                    top_docs_collector
+                        .order_by_u64_field(index.schema().get_field("timestamp_ms").unwrap()),
+                    facet_collector,
+                ),
+            )?;
+            (top_docs.into_iter().map(|(_, addr)| addr).collect(), counts)
+        }
+        OrderBy::Relevance => {
+            let (top_docs, counts) = searcher.search(&query, &(top_docs_collector, facet_collector))?;
+            (top_docs.into_iter().map(|(_, addr)| addr).collect(), counts)
+        }
+    };
 
     let snippet_generator = tantivy::SnippetGenerator::create(
         &searcher,
-        &*query,
+        &query,
         index.schema().get_field("diff").unwrap(),
     )?;
 
-    let results = top_docs
+    let results = doc_addresses
         .iter()
-        .map(|(_score, doc_address)| {
+        .map(|doc_address| {
             let retrieved_doc = searcher.doc(*doc_address)?;
 
             let project_id = retrieved_doc
@@ -358,6 +702,18 @@ pub fn search(
                 .unwrap()
                 .as_text()
                 .unwrap();
+            let author_name = retrieved_doc
+                .get_first(index.schema().get_field("author_name").unwrap())
+                .and_then(|v| v.as_text())
+                .unwrap_or_default();
+            let author_email = retrieved_doc
+                .get_first(index.schema().get_field("author_email").unwrap())
+                .and_then(|v| v.as_text())
+                .unwrap_or_default();
+            let branch = retrieved_doc
+                .get_first(index.schema().get_field("branch").unwrap())
+                .and_then(|v| v.as_text())
+                .unwrap_or_default();
             let index = retrieved_doc
                 .get_first(index.schema().get_field("index").unwrap())
                 .unwrap()
@@ -376,9 +732,23 @@ pub fn search(
                 session_id: session_id.to_string(),
                 highlighted,
                 index,
+                author_name: author_name.to_string(),
+                author_email: author_email.to_string(),
+                branch: branch.to_string(),
             })
         })
         .collect::<Result<Vec<SearchResult>>>()?;
 
-    Ok(results)
+    let extension_counts = facet_counts
+        .get("/")
+        .map(|(facet, count)| ExtensionCount {
+            extension: facet.to_string().trim_start_matches('/').to_string(),
+            count,
+        })
+        .collect();
+
+    Ok(SearchResults {
+        results,
+        extension_counts,
+    })
 }